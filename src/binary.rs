@@ -0,0 +1,265 @@
+//! A binary codec for macaroons, compatible with the V2 packet format used by
+//! [libmacaroons](https://github.com/rescrv/libmacaroons) and other ecosystems' macaroon
+//! libraries, so tokens minted with this crate can be handed to (or accepted from) them.
+//!
+//! The wire format is a version byte, followed by length-prefixed fields keyed by tag
+//! (`location` = 1, `identifier` = 2, `verification id` = 4, `signature` = 6), with each caveat's
+//! fields delimited by a single `0x00` end-of-section byte, the whole thing wrapped in URL-safe
+//! base64 for transport. See [`Macaroon::to_binary`] and [`Macaroon::from_binary`].
+
+use crypto_common::{KeyInit, KeySizeUser, OutputSizeUser};
+use generic_array::{typenum::Unsigned, GenericArray};
+use hmac::Mac;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::third_party::{CaveatEntry, ThirdPartyCaveat};
+use crate::{util, Caveat, Macaroon};
+
+const VERSION: u8 = 0x02;
+const EOS: u8 = 0x00;
+
+const TAG_LOCATION: u8 = 1;
+const TAG_IDENTIFIER: u8 = 2;
+const TAG_VID: u8 = 4;
+const TAG_SIGNATURE: u8 = 6;
+
+/// A failure to encode or decode a macaroon's V2 binary representation.
+#[derive(Debug, Error)]
+pub enum BinaryError {
+	/// The input ended before a complete field or the expected trailing signature was read.
+	#[error("binary macaroon is truncated")]
+	Truncated,
+	/// The input isn't valid URL-safe base64.
+	#[error("binary macaroon is not valid base64: {0}")]
+	Base64(#[from] base64::DecodeError),
+	/// A caveat's `identifier` field wasn't valid canonical JSON for the caveat type `C`.
+	#[error("a caveat's identifier could not be decoded as JSON: {0}")]
+	Json(#[from] serde_json::Error),
+	/// A required field was missing from a packet section.
+	#[error("binary macaroon is missing its {0} field")]
+	MissingField(&'static str),
+	/// The signature field wasn't exactly as long as the MAC's output size, so it can't be a
+	/// genuine signature from this macaroon's `M`.
+	#[error("binary macaroon's signature is {actual} bytes, expected {expected}")]
+	InvalidSignatureLength { expected: usize, actual: usize },
+	/// The input had extra bytes after what should have been the trailing signature field.
+	#[error("binary macaroon has unexpected trailing data")]
+	TrailingData,
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: usize) {
+	loop {
+		let mut byte = (value & 0x7f) as u8;
+		value >>= 7;
+		if value != 0 {
+			byte |= 0x80;
+		}
+		buf.push(byte);
+		if value == 0 {
+			break;
+		}
+	}
+}
+
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Result<usize, BinaryError> {
+	let mut result = 0usize;
+	let mut shift = 0u32;
+	loop {
+		let byte = *bytes.get(*pos).ok_or(BinaryError::Truncated)?;
+		*pos += 1;
+		result |= ((byte & 0x7f) as usize) << shift;
+		if byte & 0x80 == 0 {
+			return Ok(result);
+		}
+		shift += 7;
+	}
+}
+
+fn write_field(buf: &mut Vec<u8>, tag: u8, data: &[u8]) {
+	buf.push(tag);
+	write_varint(buf, data.len());
+	buf.extend_from_slice(data);
+}
+
+/// A single packet read from the binary stream: either a field, or the `0x00` marker that ends a
+/// section.
+enum Packet {
+	Eos,
+	Field(u8, Vec<u8>),
+}
+
+fn read_packet(bytes: &[u8], pos: &mut usize) -> Result<Packet, BinaryError> {
+	let tag = *bytes.get(*pos).ok_or(BinaryError::Truncated)?;
+	*pos += 1;
+
+	if tag == EOS {
+		return Ok(Packet::Eos);
+	}
+
+	let len = read_varint(bytes, pos)?;
+	let data = bytes
+		.get(*pos..*pos + len)
+		.ok_or(BinaryError::Truncated)?
+		.to_vec();
+	*pos += len;
+
+	Ok(Packet::Field(tag, data))
+}
+
+/// Read fields up to (and consuming) the next `0x00` end-of-section marker.
+fn read_section(bytes: &[u8], pos: &mut usize) -> Result<Vec<(u8, Vec<u8>)>, BinaryError> {
+	let mut fields = Vec::new();
+	loop {
+		match read_packet(bytes, pos)? {
+			Packet::Eos => return Ok(fields),
+			Packet::Field(tag, data) => fields.push((tag, data)),
+		}
+	}
+}
+
+fn build_caveat_entry<C>(fields: Vec<(u8, Vec<u8>)>) -> Result<CaveatEntry<C>, BinaryError>
+where
+	C: DeserializeOwned,
+{
+	let mut location = None;
+	let mut identifier = None;
+	let mut vid = None;
+
+	for (tag, data) in fields {
+		match tag {
+			TAG_LOCATION => location = Some(data),
+			TAG_IDENTIFIER => identifier = Some(data),
+			TAG_VID => vid = Some(data),
+			_ => {}
+		}
+	}
+
+	let identifier = identifier.ok_or(BinaryError::MissingField("identifier"))?;
+
+	Ok(match (location, vid) {
+		(Some(location), Some(vid)) => CaveatEntry::ThirdParty(ThirdPartyCaveat {
+			location: String::from_utf8_lossy(&location).into_owned(),
+			caveat_id: identifier,
+			vid,
+		}),
+		_ => CaveatEntry::FirstParty(serde_json::from_slice(&identifier)?),
+	})
+}
+
+impl<C, M> Macaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Serialize,
+{
+	/// Encode this macaroon as a V2 binary macaroon, wrapped in URL-safe base64 for transport.
+	/// Round-trips with [`Macaroon::from_binary`], and interoperates with other macaroon
+	/// libraries' V2 codecs.
+	pub fn to_binary(&self) -> String {
+		let mut buf = vec![VERSION];
+
+		write_field(&mut buf, TAG_IDENTIFIER, self.0.as_bytes());
+		buf.push(EOS);
+
+		for entry in &self.1 {
+			match entry {
+				CaveatEntry::FirstParty(caveat) => {
+					write_field(
+						&mut buf,
+						TAG_IDENTIFIER,
+						canonical_json::to_string(&json!(caveat))
+							.expect("JSON serialization shouldn't be fallible")
+							.as_bytes(),
+					);
+				}
+				CaveatEntry::ThirdParty(third_party) => {
+					write_field(&mut buf, TAG_LOCATION, third_party.location.as_bytes());
+					write_field(&mut buf, TAG_IDENTIFIER, &third_party.caveat_id);
+					write_field(&mut buf, TAG_VID, &third_party.vid);
+				}
+			}
+			buf.push(EOS);
+		}
+		buf.push(EOS);
+
+		write_field(&mut buf, TAG_SIGNATURE, &self.2);
+
+		util::encode(&buf)
+	}
+}
+
+/// Parse a macaroon's header, caveats and signature starting at `pos`, requiring that doing so
+/// consumes every remaining byte. That last requirement is what lets [`Macaroon::from_binary`]
+/// tell whether the leading version byte is present: parsing a header-less payload starting from
+/// its first byte (rather than its second) will, except in pathological cases, either fail outright
+/// or leave unconsumed bytes behind.
+fn parse_body<C, M>(
+	bytes: &[u8],
+	mut pos: usize,
+) -> Result<Macaroon<C, M>, BinaryError>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: DeserializeOwned,
+{
+	let header = read_section(bytes, &mut pos)?;
+	let id = header
+		.into_iter()
+		.find(|(tag, _)| *tag == TAG_IDENTIFIER)
+		.map(|(_, data)| String::from_utf8_lossy(&data).into_owned())
+		.ok_or(BinaryError::MissingField("identifier"))?;
+
+	let mut caveats = Vec::new();
+	while bytes.get(pos) != Some(&EOS) {
+		caveats.push(build_caveat_entry(read_section(bytes, &mut pos)?)?);
+	}
+	pos += 1;
+
+	let signature = match read_packet(bytes, &mut pos)? {
+		Packet::Field(tag, data) if tag == TAG_SIGNATURE => data,
+		_ => return Err(BinaryError::MissingField("signature")),
+	};
+
+	if pos != bytes.len() {
+		return Err(BinaryError::TrailingData);
+	}
+
+	let expected = <M as OutputSizeUser>::OutputSize::USIZE;
+	let actual = signature.len();
+	let signature = GenericArray::<u8, <M as OutputSizeUser>::OutputSize>::from_exact_iter(signature)
+		.ok_or(BinaryError::InvalidSignatureLength { expected, actual })?;
+
+	Ok(Macaroon(id, caveats, signature))
+}
+
+impl<C, M> Macaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Caveat + DeserializeOwned,
+{
+	/// Decode a macaroon from the V2 binary format produced by [`Macaroon::to_binary`] (or by
+	/// another macaroon library's V2 codec).
+	///
+	/// The leading version byte is optional, but its value (`0x02`) collides with the `identifier`
+	/// tag, so a single byte can't tell a versioned payload from a header-less one that happens to
+	/// start with an identifier field. When the first byte is `0x02`, this tries decoding as if it
+	/// were the version byte first, and falls back to treating it as the start of the header if
+	/// that fails for any reason other than a wrong-length signature (e.g. it doesn't account for
+	/// every byte of the input).
+	pub fn from_binary(encoded: &str) -> Result<Self, BinaryError> {
+		let bytes = util::decode(encoded)?;
+
+		if bytes.first() == Some(&VERSION) {
+			match parse_body(&bytes, 1) {
+				Ok(macaroon) => return Ok(macaroon),
+				// This error can only happen once every other byte has already been accounted for
+				// (see `parse_body`'s trailing-data check), so parsing from offset 1 was the right
+				// alignment; the payload itself, not our guess about the version byte, is invalid.
+				Err(err @ BinaryError::InvalidSignatureLength { .. }) => return Err(err),
+				Err(_) => {}
+			}
+		}
+
+		parse_body(&bytes, 0)
+	}
+}
@@ -0,0 +1,113 @@
+//! Ready-made first-party caveats for patterns nearly every deployment needs, so you don't have to
+//! reinvent correct, constant-time-safe expiry handling: [`Expiry`], [`TimeWindow`], and [`Nonce`].
+//! These serialize like any other caveat, so they slot straight into
+//! [`attenuate`](`crate::Macaroon::attenuate`) alongside your own caveat types.
+//!
+//! [`Expiry`] and [`TimeWindow`] can also be mixed into other caveat types via
+//! [`compose`](`crate::compose`). [`Nonce`] can't: its [`Context`](`crate::Caveat::Context`) is a
+//! `dyn` trait object, and [`Compose`](`crate::compose::Compose`)'s combined context requires every
+//! member's context to be `Sized`.
+
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::Caveat;
+
+/// The reason an [`Expiry`] or [`TimeWindow`] caveat rejected a token.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum TimeCaveatError {
+	/// The current time is after the caveat's allowed window.
+	#[error("This token has expired")]
+	Expired,
+	/// The current time is before the caveat's allowed window.
+	#[error("This token is not yet valid")]
+	NotYetValid,
+}
+
+/// Restricts a token to being used before a fixed point in time.
+///
+/// ```
+/// use rustmacaroon::caveats::Expiry;
+/// use rustmacaroon::Caveat;
+/// use std::time::{Duration, SystemTime};
+///
+/// let caveat = Expiry(SystemTime::now() + Duration::from_secs(60));
+/// assert!(caveat.verify(&SystemTime::now()).is_ok());
+/// assert!(caveat.verify(&(SystemTime::now() + Duration::from_secs(120))).is_err());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expiry(pub SystemTime);
+
+impl Caveat for Expiry {
+	type Error = TimeCaveatError;
+	type Context = SystemTime;
+
+	fn verify(&self, now: &SystemTime) -> Result<(), TimeCaveatError> {
+		if *now <= self.0 {
+			Ok(())
+		} else {
+			Err(TimeCaveatError::Expired)
+		}
+	}
+}
+
+/// Restricts a token to being used within a fixed window of time, inclusive of both ends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimeWindow {
+	pub not_before: SystemTime,
+	pub not_after: SystemTime,
+}
+
+impl Caveat for TimeWindow {
+	type Error = TimeCaveatError;
+	type Context = SystemTime;
+
+	fn verify(&self, now: &SystemTime) -> Result<(), TimeCaveatError> {
+		if *now < self.not_before {
+			Err(TimeCaveatError::NotYetValid)
+		} else if *now > self.not_after {
+			Err(TimeCaveatError::Expired)
+		} else {
+			Ok(())
+		}
+	}
+}
+
+/// Durable storage of which nonces have already been seen, so [`Nonce`] can enforce that a token
+/// is used at most once. Implement this against whatever store your deployment already has
+/// (Redis, a database table, ...); this crate only defines the interface.
+pub trait NonceStore {
+	/// Record that `id` has been seen. Returns `true` the first time a given `id` is recorded, and
+	/// `false` on every subsequent call with the same `id` (i.e. a replay).
+	fn record(&self, id: &[u8]) -> bool;
+}
+
+/// The reason a [`Nonce`] caveat rejected a token.
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum NonceError {
+	/// This token's nonce has already been recorded by the [`NonceStore`], so the token has
+	/// already been used.
+	#[error("This token has already been used")]
+	Replayed,
+}
+
+/// A unique token identifier (a "jti", in JWT terms) that lets a verifier enforce single use via
+/// a [`NonceStore`]. The holder doesn't need to pick anything special here beyond uniqueness;
+/// random bytes are fine.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Nonce(pub Vec<u8>);
+
+impl Caveat for Nonce {
+	type Error = NonceError;
+	type Context = dyn NonceStore;
+
+	fn verify(&self, store: &dyn NonceStore) -> Result<(), NonceError> {
+		if store.record(&self.0) {
+			Ok(())
+		} else {
+			Err(NonceError::Replayed)
+		}
+	}
+}
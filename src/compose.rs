@@ -0,0 +1,137 @@
+//! Compose several independent [`Caveat`] implementors into a single caveat type, so a macaroon
+//! can mix caveats defined by different modules without a hand-written umbrella enum.
+//!
+//! [`Compose<Head, Tail>`](`Compose`) is a coproduct: a value is either a `Head` or one of the
+//! types nested in `Tail`, terminated by the uninhabited [`CNil`]. Build one with
+//! [`Macaroon::attenuate_caveat`](`crate::Macaroon::attenuate_caveat`) rather than constructing
+//! variants by hand:
+//!
+//! ```
+//! use rustmacaroon::compose::{CNil, Compose};
+//! use rustmacaroon::{Caveat, Macaroon};
+//! use hmac::Hmac;
+//! use sha2::Sha256;
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct PathCaveat(String);
+//! impl Caveat for PathCaveat {
+//!   type Error = ();
+//!   type Context = String;
+//!   fn verify(&self, path: &String) -> Result<(), ()> {
+//!     if &self.0 == path { Ok(()) } else { Err(()) }
+//!   }
+//! }
+//!
+//! #[derive(Serialize, Deserialize)]
+//! struct RateLimitCaveat(u32);
+//! impl Caveat for RateLimitCaveat {
+//!   type Error = ();
+//!   type Context = u32;
+//!   fn verify(&self, requests_so_far: &u32) -> Result<(), ()> {
+//!     if requests_so_far <= &self.0 { Ok(()) } else { Err(()) }
+//!   }
+//! }
+//!
+//! type MyCaveats = Compose<PathCaveat, Compose<RateLimitCaveat, CNil>>;
+//!
+//! let macaroon: Macaroon<MyCaveats, Hmac<Sha256>> = Macaroon::new("id", b"key")
+//!   .attenuate_caveat(PathCaveat("/images".into()))
+//!   .attenuate_caveat(RateLimitCaveat(10));
+//!
+//! assert!(macaroon.verify(b"key", &("/images".into(), (3, ()))).is_ok());
+//! ```
+
+use std::marker::PhantomData;
+
+use crypto_common::{KeyInit, KeySizeUser};
+use hmac::Mac;
+use serde::{Deserialize, Serialize};
+
+use crate::{Caveat, Macaroon};
+
+/// The empty composed caveat type: a coproduct of zero caveat types has no values. This is what
+/// terminates a [`Compose`] chain.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CNil {}
+
+impl Caveat for CNil {
+	type Error = CNil;
+	type Context = ();
+
+	fn verify(&self, _ctx: &()) -> Result<(), CNil> {
+		match *self {}
+	}
+}
+
+/// A coproduct of caveat types: a value is either a `Head` or one of the types composed into
+/// `Tail`. See the [module documentation](`self`) for how to build and use one.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Compose<Head, Tail> {
+	Head(Head),
+	Tail(Tail),
+}
+
+impl<Head, Tail> Caveat for Compose<Head, Tail>
+where
+	Head: Caveat,
+	Head::Context: Sized,
+	Tail: Caveat,
+{
+	type Error = Compose<Head::Error, Tail::Error>;
+	/// The context for a composed caveat is every member type's own context, combined so each
+	/// variant can be verified against just its slice: `ctx.0` for `Head`, `ctx.1` (itself
+	/// structured the same way) for whichever type is active in `Tail`.
+	type Context = (Head::Context, Tail::Context);
+
+	fn verify(&self, ctx: &Self::Context) -> Result<(), Self::Error> {
+		match self {
+			Compose::Head(caveat) => caveat.verify(&ctx.0).map_err(Compose::Head),
+			Compose::Tail(caveat) => caveat.verify(&ctx.1).map_err(Compose::Tail),
+		}
+	}
+}
+
+/// Marks the type at the head of a [`Compose`] chain, used as the `Index` parameter of
+/// [`Inject`]. You won't name this directly; it's inferred.
+pub struct Here;
+
+/// Marks a type found `I` steps into the tail of a [`Compose`] chain, used as the `Index`
+/// parameter of [`Inject`]. You won't name this directly; it's inferred.
+pub struct There<I>(PhantomData<I>);
+
+/// Injects a single caveat type `C` into `Self`, a [`Compose`] chain that contains it somewhere.
+/// `Index` pins down where; it's always inferred, never named at the call site.
+pub trait Inject<C, Index> {
+	fn inject(value: C) -> Self;
+}
+
+impl<Head, Tail> Inject<Head, Here> for Compose<Head, Tail> {
+	fn inject(value: Head) -> Self {
+		Compose::Head(value)
+	}
+}
+
+impl<Head, Tail, C, Index> Inject<C, There<Index>> for Compose<Head, Tail>
+where
+	Tail: Inject<C, Index>,
+{
+	fn inject(value: C) -> Self {
+		Compose::Tail(Tail::inject(value))
+	}
+}
+
+impl<Head, Tail, M> Macaroon<Compose<Head, Tail>, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	Compose<Head, Tail>: Serialize,
+{
+	/// Add a caveat of any type composed into this macaroon's caveat set, without having to wrap
+	/// it in [`Compose`] by hand. See the [module documentation](`self`) for an example.
+	pub fn attenuate_caveat<C, Index>(self, caveat: C) -> Self
+	where
+		Compose<Head, Tail>: Inject<C, Index>,
+	{
+		self.attenuate(Compose::inject(caveat))
+	}
+}
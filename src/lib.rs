@@ -1,5 +1,9 @@
+pub mod binary;
+pub mod caveats;
+pub mod compose;
 #[cfg(test)]
 mod test;
+pub mod third_party;
 mod util;
 
 use std::fmt::Debug;
@@ -10,8 +14,17 @@ use hmac::Mac;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use thiserror::Error;
+use third_party::CaveatEntry;
 use util::{as_base64, from_base64};
 
+pub use binary::BinaryError;
+pub use compose::{CNil, Compose};
+pub use third_party::{DischargeMacaroon, ThirdPartyCaveat};
+
+/// The constant key used by the reference macaroon construction to derive a uniform root key from
+/// an arbitrary-length secret. See [`Macaroon::new_derived`].
+const KEY_GENERATOR: &[u8] = b"macaroons-key-generator";
+
 impl<T> MacHelper for T where T: Mac + KeyInit {}
 /// Helper for computing HMACs more conveniently:
 trait MacHelper: Mac + KeyInit {
@@ -32,7 +45,9 @@ trait MacHelper: Mac + KeyInit {
 /// the server-side. Clients wanting to add their own caveats to tokens don't need this trait.
 pub trait Caveat {
 	type Error;
-	type Context;
+	/// Most caveats verify against a plain context value, but this may be `?Sized` for caveats
+	/// like [`caveats::Nonce`](`crate::caveats::Nonce`) whose context is a `dyn` trait object.
+	type Context: ?Sized;
 
 	/// Verify the caveat. Use the context for any information needed to properly check caveats.
 	/// This method should return `Ok(())` if the caveat passes (i.e. the client is allowed to do what
@@ -48,7 +63,7 @@ pub trait Caveat {
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Macaroon<C, M>(
 	String,
-	Vec<C>,
+	Vec<CaveatEntry<C>>,
 	#[serde(
 		bound = "",
 		serialize_with = "as_base64::<M, _>",
@@ -73,7 +88,7 @@ where
 				.expect("JSON serialization shouldn't be fallible")
 				.into_bytes(),
 		);
-		self.1.push(caveat);
+		self.1.push(CaveatEntry::FirstParty(caveat));
 		self
 	}
 
@@ -185,31 +200,35 @@ where
 		)
 	}
 
+	/// Create a new macaroon the same way as [`Macaroon::new`], but first run the caller's key
+	/// through the key generator HMAC that the reference macaroon construction uses to turn an
+	/// arbitrary-length secret into a uniform root key.
+	///
+	/// [`verify`](`Macaroon::verify`) does not derive anything itself: to verify a macaroon minted
+	/// this way, pass it the derived key (the key generator HMAC of the secret), not the raw
+	/// secret. Use this constructor instead of [`Macaroon::new`] when a token (or its key) needs
+	/// to be portable to or from another macaroon implementation, e.g.
+	/// [libmacaroons](https://github.com/rescrv/libmacaroons), which always derives its root key
+	/// this way.
+	pub fn new_derived<T, K>(id: T, key: K) -> Self
+	where
+		T: AsRef<str>,
+		K: AsRef<[u8]>,
+	{
+		Macaroon::new(id, M::process(KEY_GENERATOR, key.as_ref()))
+	}
+
 	/// Check the signature and verify every caveat. See the documentation of
 	/// [`Macaroon::new`](`Macaroon::new`) for an example.
+	///
+	/// If this macaroon carries any third-party caveats, use
+	/// [`verify_with_discharges`](`Macaroon::verify_with_discharges`) instead; this method will
+	/// reject such a token with [`VerificationError::MissingDischarge`].
 	pub fn verify<K>(&self, key: K, ctx: &C::Context) -> Result<(), VerificationError<C>>
 	where
 		K: AsRef<[u8]>,
 	{
-		let expected_signature = std::iter::once(self.0.as_bytes().to_vec())
-			.chain(
-				self.1
-					.iter()
-					// TODO: maybe don't unwrap?
-					.map(|c| canonical_json::to_string(&json!(c)).unwrap().into_bytes()),
-			)
-			.fold(key.as_ref().to_vec(), |key, data| {
-				M::process(key, data).to_vec()
-			});
-
-		if expected_signature != self.2.as_slice() {
-			return Err(VerificationError::InvalidToken);
-		}
-
-		self.1
-			.iter()
-			.try_for_each(|caveat| caveat.verify(ctx))
-			.map_err(|e| VerificationError::CaveatFailed(e))
+		self.verify_with_discharges(key, ctx, &[])
 	}
 }
 
@@ -225,4 +244,18 @@ where
 	/// The token is either not valid as a whole or has an incorrect or forged signature.
 	#[error("The token isn't a properly constructed Macaroon or its signature is not valid")]
 	InvalidToken,
+	/// A third-party caveat in this token has no corresponding discharge macaroon among those
+	/// passed to [`verify_with_discharges`](`Macaroon::verify_with_discharges`).
+	#[error("This token has a third-party caveat with no matching discharge macaroon")]
+	MissingDischarge,
+	/// A discharge macaroon was supplied, but its signature is not the binding of its recomputed
+	/// signature to this token's signature: either it was not bound via
+	/// [`Macaroon::prepare_for_request`], or it was not minted with the right caveat key.
+	#[error("A discharge macaroon for this token was not bound to it")]
+	UnboundDischarge,
+	/// A discharge macaroon's caveat key could not be recovered from its third-party caveat's
+	/// verification id. (A failing caveat on a discharge is reported as
+	/// [`CaveatFailed`](`VerificationError::CaveatFailed`).)
+	#[error("A discharge macaroon for this token is not valid")]
+	InvalidDischarge,
 }
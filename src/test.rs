@@ -1,8 +1,14 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::ops::Range;
+use std::time::{Duration, SystemTime};
 
-use crate::{Caveat, Macaroon};
+use crate::caveats::{Expiry, Nonce, NonceError, NonceStore, TimeCaveatError, TimeWindow};
+use crate::compose::{CNil, Compose};
+use crate::third_party::DischargeMacaroon;
+use crate::{Caveat, Macaroon, VerificationError};
 use crypto_common::KeyInit;
-use hmac::Hmac;
+use hmac::{Hmac, Mac};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use sha2::Sha512;
@@ -14,7 +20,7 @@ enum CaveatError {
 	Forbidden,
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq)]
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
 struct BoolCaveat;
 impl Caveat for BoolCaveat {
 	type Error = CaveatError;
@@ -265,3 +271,362 @@ fn client_side_refine() {
 	assert_eq!(macaroon.verify(key, &18), error);
 	assert_eq!(macaroon.verify(key, &19), error);
 }
+
+#[test]
+fn expiry_rejects_after_the_deadline() {
+	let key = b"mysecretkey";
+
+	let macaroon: Macaroon<Expiry, Hmac<Sha512>> = Macaroon::new("mymacaroon", key)
+		.attenuate(Expiry(SystemTime::now() + Duration::from_secs(60)));
+
+	assert!(macaroon.verify(key, &SystemTime::now()).is_ok());
+	assert_eq!(
+		macaroon.verify(key, &(SystemTime::now() + Duration::from_secs(120))),
+		Err(crate::VerificationError::CaveatFailed(
+			TimeCaveatError::Expired
+		))
+	);
+}
+
+#[test]
+fn time_window_rejects_outside_the_window() {
+	let key = b"mysecretkey";
+	let now = SystemTime::now();
+
+	let macaroon: Macaroon<TimeWindow, Hmac<Sha512>> =
+		Macaroon::new("mymacaroon", key).attenuate(TimeWindow {
+			not_before: now + Duration::from_secs(60),
+			not_after: now + Duration::from_secs(120),
+		});
+
+	assert_eq!(
+		macaroon.verify(key, &now),
+		Err(crate::VerificationError::CaveatFailed(
+			TimeCaveatError::NotYetValid
+		))
+	);
+	assert!(macaroon
+		.verify(key, &(now + Duration::from_secs(90)))
+		.is_ok());
+	assert_eq!(
+		macaroon.verify(key, &(now + Duration::from_secs(180))),
+		Err(crate::VerificationError::CaveatFailed(
+			TimeCaveatError::Expired
+		))
+	);
+}
+
+#[derive(Default)]
+struct InMemoryNonceStore(RefCell<HashSet<Vec<u8>>>);
+impl NonceStore for InMemoryNonceStore {
+	fn record(&self, id: &[u8]) -> bool {
+		self.0.borrow_mut().insert(id.to_vec())
+	}
+}
+
+#[test]
+fn nonce_rejects_replay() {
+	let key = b"mysecretkey";
+
+	let macaroon: Macaroon<Nonce, Hmac<Sha512>> =
+		Macaroon::new("mymacaroon", key).attenuate(Nonce(b"unique-token-id".to_vec()));
+
+	let store = InMemoryNonceStore::default();
+
+	assert!(macaroon.verify(key, &store).is_ok());
+	assert_eq!(
+		macaroon.verify(key, &store),
+		Err(crate::VerificationError::CaveatFailed(NonceError::Replayed))
+	);
+}
+
+#[test]
+fn new_derived_applies_the_spec_key_generator() {
+	let id = "mymacaroon";
+	let secret = b"mysecretkey";
+
+	let derived: Macaroon<BoolCaveat, Hmac<Sha512>> = Macaroon::new_derived(id, secret);
+
+	// The reference construction's root key is always HMAC("macaroons-key-generator", secret),
+	// whatever this crate's own internals look like.
+	let expected_key = <Hmac<Sha512> as Mac>::new_from_slice(b"macaroons-key-generator")
+		.unwrap()
+		.chain_update(secret)
+		.finalize()
+		.into_bytes();
+	let expected: Macaroon<BoolCaveat, Hmac<Sha512>> = Macaroon::new(id, expected_key);
+
+	assert_eq!(derived.tail(), expected.tail());
+	assert_ne!(
+		derived.tail(),
+		Macaroon::<BoolCaveat, Hmac<Sha512>>::new(id, secret).tail()
+	);
+
+	// Verifying a derived macaroon takes the same derived key used to mint it, not the raw secret.
+	assert!(derived.verify(expected_key, &true).is_ok());
+}
+
+fn third_party_caveat_fixture() -> (
+	Macaroon<BoolCaveat, Hmac<Sha512>>,
+	DischargeMacaroon<BoolCaveat, Hmac<Sha512>>,
+) {
+	let key = b"mysecretkey";
+
+	let (root, caveat_key) = Macaroon::<BoolCaveat, Hmac<Sha512>>::new("mymacaroon", key)
+		.add_third_party_caveat("https://auth.example", b"opaque-id".to_vec());
+
+	let discharge = DischargeMacaroon::new(b"opaque-id", &caveat_key);
+
+	(root, discharge)
+}
+
+#[test]
+fn third_party_caveat_round_trip_verifies() {
+	let key = b"mysecretkey";
+	let (root, discharge) = third_party_caveat_fixture();
+
+	let bound = root.prepare_for_request(&[discharge]);
+
+	assert!(root.verify_with_discharges(key, &true, &bound).is_ok());
+}
+
+#[test]
+fn third_party_caveat_without_a_discharge_is_missing() {
+	let key = b"mysecretkey";
+	let (root, _discharge) = third_party_caveat_fixture();
+
+	assert_eq!(
+		root.verify_with_discharges(key, &true, &[]),
+		Err(VerificationError::MissingDischarge)
+	);
+}
+
+#[test]
+fn unbound_discharge_is_rejected() {
+	let key = b"mysecretkey";
+	let (root, discharge) = third_party_caveat_fixture();
+
+	// `discharge` was never passed through `prepare_for_request`, so it isn't bound to `root`.
+	assert_eq!(
+		root.verify_with_discharges(key, &true, &[discharge]),
+		Err(VerificationError::UnboundDischarge)
+	);
+}
+
+#[test]
+fn discharge_with_wrong_caveat_key_is_rejected() {
+	let key = b"mysecretkey";
+	let (root, _discharge) = third_party_caveat_fixture();
+
+	let forged_discharge = DischargeMacaroon::new(b"opaque-id", b"not the real caveat key");
+	let bound = root.prepare_for_request(&[forged_discharge]);
+
+	assert_eq!(
+		root.verify_with_discharges(key, &true, &bound),
+		Err(VerificationError::UnboundDischarge)
+	);
+}
+
+#[test]
+fn discharge_can_carry_its_own_caveats() {
+	let key = b"mysecretkey";
+	let (root, discharge) = third_party_caveat_fixture();
+	let discharge = discharge.attenuate(BoolCaveat);
+
+	let bound = root.prepare_for_request(&[discharge]);
+
+	// `BoolCaveat` is checked against the same context passed to `verify_with_discharges`, so this
+	// fails once the discharge's own caveat can no longer pass.
+	assert!(root.verify_with_discharges(key, &true, &bound).is_ok());
+	assert_eq!(
+		root.verify_with_discharges(key, &false, &bound),
+		Err(VerificationError::CaveatFailed(CaveatError::Forbidden))
+	);
+}
+
+#[test]
+fn discharge_json_round_trip_verifies() {
+	let key = b"mysecretkey";
+	let (root, discharge) = third_party_caveat_fixture();
+
+	let serialized = serde_json::to_string(&discharge).expect("failed to serialize discharge");
+	let discharge: DischargeMacaroon<BoolCaveat, Hmac<Sha512>> =
+		serde_json::from_str(&serialized).expect("failed to deserialize discharge");
+
+	let bound = root.prepare_for_request(&[discharge]);
+
+	assert!(root.verify_with_discharges(key, &true, &bound).is_ok());
+}
+
+#[test]
+fn discharge_binary_round_trip_verifies() {
+	let key = b"mysecretkey";
+	let (root, discharge) = third_party_caveat_fixture();
+
+	let encoded = discharge.to_binary();
+	let discharge: DischargeMacaroon<BoolCaveat, Hmac<Sha512>> =
+		DischargeMacaroon::from_binary(&encoded).expect("failed to decode a valid binary discharge");
+
+	let bound = root.prepare_for_request(&[discharge]);
+
+	assert!(root.verify_with_discharges(key, &true, &bound).is_ok());
+}
+
+#[test]
+fn binary_round_trip_verifies() {
+	let key = b"mysecretkey";
+
+	let macaroon: Macaroon<RangeCaveat, Hmac<Sha512>> =
+		Macaroon::new("mymacaroon", key).attenuate(RangeCaveat(10..20));
+
+	let encoded = macaroon.to_binary();
+	let decoded: Macaroon<RangeCaveat, Hmac<Sha512>> =
+		Macaroon::from_binary(&encoded).expect("failed to decode a valid binary macaroon");
+
+	assert!(decoded.verify(key, &15).is_ok());
+}
+
+#[test]
+fn binary_from_invalid_base64_rejects() {
+	let result: Result<Macaroon<RangeCaveat, Hmac<Sha512>>, _> =
+		Macaroon::from_binary("not valid base64!!");
+
+	assert!(matches!(result, Err(crate::BinaryError::Base64(_))));
+}
+
+#[test]
+fn binary_from_truncated_input_rejects() {
+	let key = b"mysecretkey";
+	let macaroon: Macaroon<RangeCaveat, Hmac<Sha512>> =
+		Macaroon::new("mymacaroon", key).attenuate(RangeCaveat(10..20));
+
+	let mut bytes = crate::util::decode(&macaroon.to_binary()).unwrap();
+	bytes.truncate(bytes.len() - 4);
+	let truncated = crate::util::encode(&bytes);
+
+	let result: Result<Macaroon<RangeCaveat, Hmac<Sha512>>, _> = Macaroon::from_binary(&truncated);
+
+	assert!(matches!(result, Err(crate::BinaryError::Truncated)));
+}
+
+#[test]
+fn binary_with_wrong_signature_length_rejects_instead_of_panicking() {
+	let key = b"mysecretkey";
+	let macaroon: Macaroon<RangeCaveat, Hmac<Sha512>> = Macaroon::new("mymacaroon", key);
+
+	let mut bytes = crate::util::decode(&macaroon.to_binary()).unwrap();
+
+	// The trailing signature field is HMAC-SHA512 output (64 bytes, a one-byte varint length), tag
+	// 6 per the binary module's wire format docs: drop the last signature byte and shrink the
+	// length prefix to match, so the packet is well-formed but the signature is the wrong size.
+	let len_idx = bytes.len() - 64 - 1;
+	assert_eq!(bytes[len_idx - 1], 6);
+	assert_eq!(bytes[len_idx], 64);
+	bytes[len_idx] = 63;
+	bytes.pop();
+	let malformed = crate::util::encode(&bytes);
+
+	let result: Result<Macaroon<RangeCaveat, Hmac<Sha512>>, _> = Macaroon::from_binary(&malformed);
+
+	assert_eq!(
+		result.err().map(|err| err.to_string()),
+		Some("binary macaroon's signature is 63 bytes, expected 64".to_string())
+	);
+}
+
+#[test]
+fn binary_without_the_version_byte_still_decodes() {
+	let key = b"mysecretkey";
+	let macaroon: Macaroon<RangeCaveat, Hmac<Sha512>> =
+		Macaroon::new("mymacaroon", key).attenuate(RangeCaveat(10..20));
+
+	// Dropping the leading version byte makes the payload start directly with an identifier field
+	// (tag 2), which is byte-identical to "version byte present, tag 2" for that one byte. This must
+	// still decode correctly rather than misparsing one byte out of sync, since other macaroon
+	// libraries may omit it.
+	let mut bytes = crate::util::decode(&macaroon.to_binary()).unwrap();
+	bytes.remove(0);
+	let versionless = crate::util::encode(&bytes);
+
+	let decoded: Macaroon<RangeCaveat, Hmac<Sha512>> =
+		Macaroon::from_binary(&versionless).expect("failed to decode a version-less macaroon");
+
+	assert!(decoded.verify(key, &15).is_ok());
+}
+
+type ThreeWayCaveat = Compose<BoolCaveat, Compose<RangeCaveat, Compose<Expiry, CNil>>>;
+
+fn three_way_macaroon(key: &[u8], expiry: SystemTime) -> Macaroon<ThreeWayCaveat, Hmac<Sha512>> {
+	Macaroon::new("mymacaroon", key)
+		.attenuate_caveat(BoolCaveat)
+		.attenuate_caveat(RangeCaveat(10..20))
+		.attenuate_caveat(Expiry(expiry))
+}
+
+#[test]
+fn compose_three_member_chain_verifies() {
+	let key = b"mysecretkey";
+	let not_yet_expired = SystemTime::now() + Duration::from_secs(60);
+	let macaroon = three_way_macaroon(key, not_yet_expired);
+
+	assert!(macaroon
+		.verify(key, &(true, (15, (SystemTime::now(), ()))))
+		.is_ok());
+	assert!(macaroon
+		.verify(key, &(false, (15, (SystemTime::now(), ()))))
+		.is_err());
+	assert!(macaroon
+		.verify(key, &(true, (5, (SystemTime::now(), ()))))
+		.is_err());
+	let expired = not_yet_expired + Duration::from_secs(60);
+	assert!(macaroon.verify(key, &(true, (15, (expired, ())))).is_err());
+}
+
+#[test]
+fn compose_failure_unwraps_to_matching_variant() {
+	let key = b"mysecretkey";
+	let not_yet_expired = SystemTime::now() + Duration::from_secs(60);
+	let macaroon = three_way_macaroon(key, not_yet_expired);
+
+	assert_eq!(
+		macaroon.verify(key, &(false, (15, (SystemTime::now(), ())))),
+		Err(VerificationError::CaveatFailed(Compose::Head(
+			CaveatError::Forbidden
+		)))
+	);
+	assert_eq!(
+		macaroon.verify(key, &(true, (5, (SystemTime::now(), ())))),
+		Err(VerificationError::CaveatFailed(Compose::Tail(
+			Compose::Head(CaveatError::Forbidden)
+		)))
+	);
+	let expired = not_yet_expired + Duration::from_secs(60);
+	assert_eq!(
+		macaroon.verify(key, &(true, (15, (expired, ())))),
+		Err(VerificationError::CaveatFailed(Compose::Tail(
+			Compose::Tail(Compose::Head(TimeCaveatError::Expired))
+		)))
+	);
+}
+
+#[test]
+fn compose_json_round_trip() {
+	let key = b"mysecretkey";
+	let not_yet_expired = SystemTime::now() + Duration::from_secs(60);
+	let original_macaroon = three_way_macaroon(key, not_yet_expired);
+
+	let serialized =
+		serde_json::to_string(&original_macaroon).expect("Failed to serialize macaroon");
+	let macaroon: Macaroon<ThreeWayCaveat, Hmac<Sha512>> =
+		serde_json::from_str(&serialized).expect("Failed to deserialize macaroon");
+
+	assert!(macaroon
+		.verify(key, &(true, (15, (SystemTime::now(), ()))))
+		.is_ok());
+	assert_eq!(
+		macaroon.verify(key, &(false, (15, (SystemTime::now(), ())))),
+		Err(VerificationError::CaveatFailed(Compose::Head(
+			CaveatError::Forbidden
+		)))
+	);
+}
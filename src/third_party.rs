@@ -0,0 +1,315 @@
+//! Third-party caveats let a holder delegate part of a check to another service instead of
+//! enforcing it locally. See the [macaroons paper](http://research.google.com/pubs/pub41892.html)
+//! for the scheme this module implements; a gentler introduction is [fly.io's blog
+//! post](https://fly.io/blog/macaroons-escalated-quickly/).
+//!
+//! To add a third-party caveat, call [`Macaroon::add_third_party_caveat`]. This hands back the
+//! attenuated macaroon and a fresh caveat key, which you must get to the third party out-of-band
+//! (typically by encrypting it, along with whatever predicate it should enforce, into the
+//! `caveat_id` you supply). The third party issues a [`DischargeMacaroon`] rooted at that key.
+//!
+//! Before sending a request, the holder must bind every discharge to the root macaroon with
+//! [`Macaroon::prepare_for_request`]; an unbound discharge will be rejected. The verifier then
+//! checks everything at once with [`Macaroon::verify_with_discharges`].
+
+use crypto_common::{KeyInit, KeySizeUser, OutputSizeUser};
+use generic_array::GenericArray;
+use hmac::Mac;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use chacha20poly1305::{
+	aead::{Aead, Error as AeadError},
+	ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::{Caveat, MacHelper, Macaroon, VerificationError};
+
+const NONCE_LEN: usize = 12;
+
+/// One entry in a macaroon's caveat chain: either a first-party caveat, enforced locally by
+/// [`Caveat::verify`], or a [`ThirdPartyCaveat`] that must be discharged by another service.
+///
+/// This is an implementation detail of how [`Macaroon`] stores caveats; you won't construct one
+/// directly. [`Macaroon::attenuate`] wraps first-party caveats for you, and
+/// [`Macaroon::add_third_party_caveat`] wraps third-party ones.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum CaveatEntry<C> {
+	FirstParty(C),
+	ThirdParty(ThirdPartyCaveat),
+}
+
+/// A third-party caveat: a pointer to a check that must be delegated to another service.
+///
+/// `caveat_id` is an opaque blob, meaningful only to the third party at `location`, that lets it
+/// recover the caveat key and whatever predicate it should enforce. `vid` is that same caveat key,
+/// encrypted so that only someone who can recompute the macaroon's running signature up to this
+/// point (i.e. the verifier) can recover it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ThirdPartyCaveat {
+	/// Where the third party that can discharge this caveat can be reached. Purely advisory; this
+	/// crate never dereferences it.
+	pub location: String,
+	pub caveat_id: Vec<u8>,
+	pub(crate) vid: Vec<u8>,
+}
+
+/// A macaroon issued by a third party to discharge one of its caveats on a root macaroon.
+///
+/// Structurally this is just a [`Macaroon`] rooted at the caveat key the delegator generated, with
+/// its id set to the URL-safe base64 encoding of the `caveat_id` the delegator supplied. Third parties can attach their own
+/// first-party caveats with [`attenuate`](`DischargeMacaroon::attenuate`) exactly as they would on
+/// any other macaroon.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DischargeMacaroon<C, M>(
+	#[serde(bound(serialize = "C: Serialize", deserialize = "C: serde::de::DeserializeOwned"))]
+	Macaroon<C, M>,
+)
+where
+	M: OutputSizeUser;
+
+impl<C, M> DischargeMacaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Caveat + Serialize,
+{
+	/// Create a discharge macaroon for the caveat key handed out by
+	/// [`Macaroon::add_third_party_caveat`].
+	pub fn new(caveat_id: &[u8], caveat_key: &[u8]) -> Self {
+		DischargeMacaroon(Macaroon::new(caveat_id_to_macaroon_id(caveat_id), caveat_key))
+	}
+
+	/// Add a first-party caveat of the third party's own, e.g. restricting how long the discharge
+	/// is valid for.
+	pub fn attenuate(self, caveat: C) -> Self {
+		DischargeMacaroon(self.0.attenuate(caveat))
+	}
+}
+
+impl<C, M> DischargeMacaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Serialize,
+{
+	/// Encode this discharge as a V2 binary macaroon, exactly like
+	/// [`Macaroon::to_binary`](`crate::Macaroon::to_binary`). Round-trips with
+	/// [`DischargeMacaroon::from_binary`].
+	pub fn to_binary(&self) -> String {
+		self.0.to_binary()
+	}
+}
+
+impl<C, M> DischargeMacaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Caveat + serde::de::DeserializeOwned,
+{
+	/// Decode a discharge from the V2 binary format produced by [`DischargeMacaroon::to_binary`],
+	/// exactly like [`Macaroon::from_binary`](`crate::Macaroon::from_binary`).
+	pub fn from_binary(encoded: &str) -> Result<Self, crate::BinaryError> {
+		Macaroon::from_binary(encoded).map(DischargeMacaroon)
+	}
+}
+
+impl<C, M> DischargeMacaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Clone + Serialize,
+{
+	/// Bind this discharge to a root macaroon's signature, replacing its own signature with the
+	/// binding. A verifier recomputes the same value and rejects any discharge whose signature
+	/// doesn't match it, which stops a discharge minted for one root macaroon from being replayed
+	/// against another. The binding depends only on the two signatures, not on any request.
+	///
+	/// [`Macaroon::prepare_for_request`] does this for every discharge a request needs; call this
+	/// directly only if you're managing discharges yourself.
+	pub fn bind(&self, root_signature: &GenericArray<u8, M::OutputSize>) -> Self {
+		let zero_key = GenericArray::<u8, M::OutputSize>::default();
+		let bound = M::process(
+			&zero_key,
+			[root_signature.as_slice(), self.0.tail().as_slice()].concat(),
+		);
+		DischargeMacaroon(Macaroon(self.0 .0.clone(), self.0 .1.clone(), bound))
+	}
+}
+
+impl<C, M> Macaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Clone + Serialize,
+{
+	/// Delegate a check to a third party. Picks a fresh caveat key, encrypts it under this
+	/// macaroon's current signature to produce the `vid` the verifier will need to recover it, and
+	/// chains the caveat into the signature.
+	///
+	/// Returns the attenuated macaroon along with the caveat key, which you must get to the third
+	/// party out-of-band (e.g. by encrypting it into `caveat_id`, which the third party alone knows
+	/// how to decode). The third party uses the key to build a [`DischargeMacaroon`].
+	pub fn add_third_party_caveat<T>(mut self, location: T, caveat_id: Vec<u8>) -> (Self, Vec<u8>)
+	where
+		T: AsRef<str>,
+	{
+		let mut caveat_key = vec![0u8; 32];
+		OsRng.fill_bytes(&mut caveat_key);
+
+		let vid = encrypt_caveat_key(&self.2, &caveat_key);
+
+		self.2 = M::process(&self.2, [vid.as_slice(), caveat_id.as_slice()].concat());
+		self.1.push(CaveatEntry::ThirdParty(ThirdPartyCaveat {
+			location: location.as_ref().to_string(),
+			caveat_id,
+			vid,
+		}));
+
+		(self, caveat_key)
+	}
+
+	/// Bind a set of discharge macaroons to this macaroon's signature, ready to be sent alongside
+	/// it in a request. The bound discharges are accepted only alongside this exact macaroon (its
+	/// current signature); they are not tied to a particular request.
+	pub fn prepare_for_request(
+		&self,
+		discharges: &[DischargeMacaroon<C, M>],
+	) -> Vec<DischargeMacaroon<C, M>> {
+		discharges.iter().map(|d| d.bind(&self.2)).collect()
+	}
+}
+
+impl<C, M> Macaroon<C, M>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Caveat + Serialize,
+{
+	/// Check the signature, verify every first-party caveat, and discharge every third-party
+	/// caveat against the macaroons in `discharges`. `discharges` must have already been bound to
+	/// this macaroon via [`prepare_for_request`](`Macaroon::prepare_for_request`).
+	///
+	/// If this macaroon has no third-party caveats, `discharges` may be empty; this is exactly what
+	/// [`verify`](`Macaroon::verify`) does.
+	pub fn verify_with_discharges<K>(
+		&self,
+		key: K,
+		ctx: &C::Context,
+		discharges: &[DischargeMacaroon<C, M>],
+	) -> Result<(), VerificationError<C>>
+	where
+		K: AsRef<[u8]>,
+	{
+		let mut caveats = Vec::new();
+		let signature = verify_chain(self, key.as_ref(), discharges, &self.2, &mut caveats)?;
+
+		if signature != self.2 {
+			return Err(VerificationError::InvalidToken);
+		}
+
+		// Only run caveat verification once the whole chain (root and every discharge) is known to
+		// be validly signed and bound. Otherwise a forged token could still trigger the side effects
+		// of a stateful caveat like `caveats::Nonce` before being rejected, e.g. burning a nonce that
+		// the legitimate holder hasn't actually used yet.
+		for caveat in caveats {
+			caveat.verify(ctx).map_err(VerificationError::CaveatFailed)?;
+		}
+
+		Ok(())
+	}
+}
+
+/// Recompute a macaroon's running signature and recursively verify every discharge's binding,
+/// collecting every first-party caveat encountered (root and discharges alike) into `caveats`
+/// along the way instead of verifying them immediately. Returns the final signature so callers can
+/// compare it against the macaroon's stored one (for the root) or fold it into a binding check
+/// (for a discharge).
+///
+/// `root_signature` is always the top-level macaroon's own (final) signature: that's what a
+/// discharge is bound against, however deeply nested the third-party caveat that needs it is.
+fn verify_chain<'a, C, M>(
+	macaroon: &'a Macaroon<C, M>,
+	key: &[u8],
+	discharges: &'a [DischargeMacaroon<C, M>],
+	root_signature: &GenericArray<u8, M::OutputSize>,
+	caveats: &mut Vec<&'a C>,
+) -> Result<GenericArray<u8, M::OutputSize>, VerificationError<C>>
+where
+	M: Mac + KeySizeUser + KeyInit,
+	C: Caveat + Serialize,
+{
+	let mut signature = M::process(key, macaroon.0.as_bytes());
+
+	for entry in &macaroon.1 {
+		match entry {
+			CaveatEntry::FirstParty(caveat) => {
+				signature = M::process(
+					&signature,
+					// TODO: maybe don't unwrap?
+					canonical_json::to_string(&json!(caveat)).unwrap().into_bytes(),
+				);
+				caveats.push(caveat);
+			}
+			CaveatEntry::ThirdParty(third_party) => {
+				let discharge = discharges
+					.iter()
+					.find(|d| d.0 .0 == caveat_id_to_macaroon_id(&third_party.caveat_id))
+					.ok_or(VerificationError::MissingDischarge)?;
+
+				let caveat_key = decrypt_caveat_key(&signature, &third_party.vid)
+					.map_err(|_| VerificationError::InvalidDischarge)?;
+
+				let discharge_signature =
+					verify_chain(&discharge.0, &caveat_key, discharges, root_signature, caveats)?;
+
+				let zero_key = GenericArray::<u8, M::OutputSize>::default();
+				let expected_bound = M::process(
+					&zero_key,
+					[root_signature.as_slice(), discharge_signature.as_slice()].concat(),
+				);
+				if expected_bound != discharge.0 .2 {
+					return Err(VerificationError::UnboundDischarge);
+				}
+
+				signature = M::process(
+					&signature,
+					[third_party.vid.as_slice(), third_party.caveat_id.as_slice()].concat(),
+				);
+			}
+		}
+	}
+
+	Ok(signature)
+}
+
+/// A discharge macaroon's id is just its `caveat_id`, base64-encoded so it fits the `String` id
+/// that [`Macaroon::new`] expects (a `caveat_id` is an arbitrary byte string, not necessarily
+/// valid UTF-8).
+fn caveat_id_to_macaroon_id(caveat_id: &[u8]) -> String {
+	use base64::{engine::general_purpose::URL_SAFE, Engine};
+	URL_SAFE.encode(caveat_id)
+}
+
+/// Encrypt a caveat key under a macaroon's current signature, so that only someone who can
+/// recompute that signature can recover it. The encryption key is derived from the signature via
+/// SHA-256 so that it has the fixed width `ChaCha20Poly1305` needs, regardless of `M`'s output
+/// size.
+fn encrypt_caveat_key(signature: &[u8], caveat_key: &[u8]) -> Vec<u8> {
+	let cipher = ChaCha20Poly1305::new(Key::from_slice(&Sha256::digest(signature)));
+
+	let mut nonce_bytes = [0u8; NONCE_LEN];
+	OsRng.fill_bytes(&mut nonce_bytes);
+
+	let ciphertext = cipher
+		.encrypt(Nonce::from_slice(&nonce_bytes), caveat_key)
+		.expect("encrypting a caveat key shouldn't fail");
+
+	[nonce_bytes.as_slice(), ciphertext.as_slice()].concat()
+}
+
+fn decrypt_caveat_key(signature: &[u8], vid: &[u8]) -> Result<Vec<u8>, AeadError> {
+	if vid.len() < NONCE_LEN {
+		return Err(AeadError);
+	}
+	let (nonce_bytes, ciphertext) = vid.split_at(NONCE_LEN);
+
+	let cipher = ChaCha20Poly1305::new(Key::from_slice(&Sha256::digest(signature)));
+	cipher.decrypt(Nonce::from_slice(nonce_bytes), ciphertext)
+}
@@ -3,6 +3,16 @@ use crypto_common::OutputSizeUser;
 use generic_array::GenericArray;
 use serde::{Deserialize, Deserializer, Serializer};
 
+/// URL-safe base64 encode, shared by the serde helpers below and by the binary (V2) codec.
+pub(crate) fn encode(bytes: &[u8]) -> String {
+	URL_SAFE.encode(bytes)
+}
+
+/// URL-safe base64 decode, shared by the serde helpers below and by the binary (V2) codec.
+pub(crate) fn decode(string: &str) -> Result<Vec<u8>, base64::DecodeError> {
+	URL_SAFE.decode(string)
+}
+
 // https://github.com/serde-rs/serde/issues/661
 pub fn as_base64<M, S>(
 	data: &GenericArray<u8, <M as OutputSizeUser>::OutputSize>,
@@ -12,7 +22,7 @@ where
 	S: Serializer,
 	M: OutputSizeUser,
 {
-	serializer.serialize_str(&URL_SAFE.encode(&data[..]))
+	serializer.serialize_str(&encode(&data[..]))
 }
 
 pub fn from_base64<'de, M, D>(
@@ -24,10 +34,6 @@ where
 {
 	use serde::de::Error;
 	String::deserialize(deserializer)
-		.and_then(|string| {
-			URL_SAFE
-				.decode(&string)
-				.map_err(|err| Error::custom(err.to_string()))
-		})
+		.and_then(|string| decode(&string).map_err(|err| Error::custom(err.to_string())))
 		.map(|bytes| GenericArray::from_slice(&bytes).clone())
 }